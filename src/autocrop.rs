@@ -0,0 +1,75 @@
+//! Automatic letterbox/pillarbox detection.
+//!
+//! Scans inward from each edge of the [`PixelGrid`] graph until the edge
+//! energy jumps above a threshold, finding the actual content rectangle.
+
+use image::GrayImage;
+use petgraph::visit::{EdgeRef as _, IntoEdgeReferences};
+
+use crate::gbis::PixelGrid;
+
+/// Edge weights are normalized to `0.0..1.0`; a row or column boundary whose
+/// summed edge weight is below this is considered part of a flat bar rather
+/// than real content.
+const ENERGY_THRESHOLD: f32 = 0.02;
+
+/// Detect the content rectangle of `image` as `(x, y, width, height)`,
+/// trimming any letterbox/pillarbox bars found along its edges.
+pub fn detect_crop(image: &GrayImage) -> (u32, u32, u32, u32) {
+    let (width, height) = (image.width(), image.height());
+    let view = PixelGrid(image);
+
+    // Accumulate, for each row and column boundary (there are height-1 row
+    // boundaries and width-1 column boundaries), the total edge weight
+    // crossing it. A boundary that stays near zero all the way across is
+    // flat, which is the signature of a bar rather than real content.
+    let mut row_energy = vec![0f32; height.saturating_sub(1) as usize];
+    let mut col_energy = vec![0f32; width.saturating_sub(1) as usize];
+
+    for edge in (&view).edge_references() {
+        let a = edge.source();
+        let b = edge.target();
+        let weight = *edge.weight();
+
+        if a.x != b.x {
+            col_energy[a.x.min(b.x) as usize] += weight;
+        }
+        if a.y != b.y {
+            row_energy[a.y.min(b.y) as usize] += weight;
+        }
+    }
+
+    // A frame whose rows or columns are all flat (a solid black frame, a
+    // fade, etc.) has no content boundary at all, and the two runs meet or
+    // cross in the middle. Fall back to the untrimmed frame rather than let
+    // `bottom`/`right` underflow below `top`/`left`.
+    let top = leading_flat_run(&row_energy);
+    let bottom_run = leading_flat_run_rev(&row_energy);
+    let left = leading_flat_run(&col_energy);
+    let right_run = leading_flat_run_rev(&col_energy);
+
+    if top + bottom_run >= height || left + right_run >= width {
+        return (0, 0, width, height);
+    }
+
+    let bottom = height - 1 - bottom_run;
+    let right = width - 1 - right_run;
+
+    (left, top, right - left + 1, bottom - top + 1)
+}
+
+/// Count the leading entries that are all below the threshold.
+fn leading_flat_run(energy: &[f32]) -> u32 {
+    energy
+        .iter()
+        .take_while(|&&e| e < ENERGY_THRESHOLD)
+        .count() as u32
+}
+
+fn leading_flat_run_rev(energy: &[f32]) -> u32 {
+    energy
+        .iter()
+        .rev()
+        .take_while(|&&e| e < ENERGY_THRESHOLD)
+        .count() as u32
+}
@@ -0,0 +1,148 @@
+//! SQLite-backed storage for the reference library built by `fetch`.
+
+use rusqlite::{params, Connection};
+
+use crate::Hash;
+
+/// Schema version written to `PRAGMA user_version`, bumped whenever the
+/// table layout below changes.
+const SCHEMA_VERSION: i64 = 1;
+
+/// A single reference still pulled from TMDB for an episode.
+#[derive(Debug, Clone)]
+pub struct Still {
+    pub file_path: String,
+    pub width: u32,
+    pub height: u32,
+    pub hash: Hash,
+}
+
+/// Handle to the reference library database.
+pub struct Db {
+    conn: Connection,
+}
+
+impl Db {
+    /// Open (creating if necessary) the database at `path`, running any
+    /// pending schema migrations.
+    pub fn open(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        let db = Db { conn };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    fn migrate(&self) -> anyhow::Result<()> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version < SCHEMA_VERSION {
+            self.conn.execute_batch(
+                "
+                CREATE TABLE IF NOT EXISTS episodes (
+                    id              INTEGER PRIMARY KEY,
+                    tv_id           INTEGER NOT NULL,
+                    season          INTEGER NOT NULL,
+                    episode_number  INTEGER NOT NULL,
+                    UNIQUE (tv_id, season, episode_number)
+                );
+                CREATE TABLE IF NOT EXISTS files (
+                    id          INTEGER PRIMARY KEY,
+                    episode_id  INTEGER NOT NULL REFERENCES episodes (id) ON DELETE CASCADE,
+                    file_path   TEXT NOT NULL,
+                    width       INTEGER NOT NULL,
+                    height      INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS hashes (
+                    file_id  INTEGER PRIMARY KEY REFERENCES files (id) ON DELETE CASCADE,
+                    hash     BLOB NOT NULL
+                );
+                ",
+            )?;
+            self.conn
+                .pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+
+        Ok(())
+    }
+
+    /// Insert or replace all stills for one episode, leaving every other
+    /// episode in the database untouched.
+    pub fn put_episode(
+        &mut self,
+        tv_id: i32,
+        season: i32,
+        episode_number: i32,
+        stills: &[Still],
+    ) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+
+        tx.execute(
+            "INSERT OR REPLACE INTO episodes (tv_id, season, episode_number) VALUES (?1, ?2, ?3)",
+            params![tv_id, season, episode_number],
+        )?;
+        let episode_id: i64 = tx.query_row(
+            "SELECT id FROM episodes WHERE tv_id = ?1 AND season = ?2 AND episode_number = ?3",
+            params![tv_id, season, episode_number],
+            |row| row.get(0),
+        )?;
+
+        tx.execute(
+            "DELETE FROM files WHERE episode_id = ?1",
+            params![episode_id],
+        )?;
+        for still in stills {
+            tx.execute(
+                "INSERT INTO files (episode_id, file_path, width, height) VALUES (?1, ?2, ?3, ?4)",
+                params![episode_id, still.file_path, still.width, still.height],
+            )?;
+            let file_id = tx.last_insert_rowid();
+            tx.execute(
+                "INSERT INTO hashes (file_id, hash) VALUES (?1, ?2)",
+                params![file_id, still.hash.to_bytes().as_slice()],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Load the thumbnail hashes for every episode of a season that falls
+    /// within `[min, max]`, grouped by episode number in ascending order.
+    /// Episodes outside the range are filtered out in SQL; every matching
+    /// row is collected into memory before this returns.
+    pub fn candidate_episodes(
+        &self,
+        tv_id: i32,
+        season: i32,
+        min: Option<i32>,
+        max: Option<i32>,
+    ) -> anyhow::Result<Vec<(i32, Vec<Hash>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT e.episode_number, h.hash
+             FROM episodes e
+             JOIN files f ON f.episode_id = e.id
+             JOIN hashes h ON h.file_id = f.id
+             WHERE e.tv_id = ?1 AND e.season = ?2
+               AND (?3 IS NULL OR e.episode_number >= ?3)
+               AND (?4 IS NULL OR e.episode_number <= ?4)
+             ORDER BY e.episode_number",
+        )?;
+        let mut rows = stmt.query(params![tv_id, season, min, max])?;
+
+        let mut episodes: Vec<(i32, Vec<Hash>)> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let episode_number: i32 = row.get(0)?;
+            let raw: Vec<u8> = row.get(1)?;
+            let hash = Hash::from_bytes(&raw)?;
+
+            match episodes.last_mut() {
+                Some((number, hashes)) if *number == episode_number => hashes.push(hash),
+                _ => episodes.push((episode_number, vec![hash])),
+            }
+        }
+
+        Ok(episodes)
+    }
+}
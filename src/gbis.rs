@@ -2,33 +2,40 @@
 //!
 //! Implemented based on the paper "Efficient Graph-Based Image Segmentation" by
 //! Felzenszwalb and Huttenlocher (2004)
+//!
+//! With the `serde` feature enabled (mirroring petgraph's own `serde-1`
+//! feature), [`Segmentation`], [`Component`], and [`PixelCoordinate`] gain
+//! `Serialize`/`Deserialize` impls so a segmentation can be cached or
+//! shipped between processes instead of always being recomputed.
 
 pub mod pixel_grid;
+mod union_find;
+
+use std::collections::HashMap;
 
-use image::{imageops::blur, GenericImageView, Luma};
+use image::{imageops::blur, GenericImageView, ImageBuffer, Luma};
 pub use pixel_grid::PixelGrid;
+pub use union_find::DisjointComponents;
 
+use petgraph::graph::{NodeIndex, UnGraph};
 use petgraph::visit::{Data, EdgeRef, GraphBase, IntoEdgeReferences, NodeIndexable};
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Component {
     pub int_diff: f32,
     pub node_count: usize,
 }
 
-#[derive(Clone)]
-enum ComponentSlot {
-    Here(Component),
-    There(usize),
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PixelCoordinate {
     pub x: u32,
     pub y: u32,
 }
 
 #[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Segmentation {
     /// Map from node indexes to component indexes
     pub node_components: Vec<usize>,
@@ -37,37 +44,287 @@ pub struct Segmentation {
     pub components: Vec<Component>,
 }
 
-pub fn segment<'a, G>(graph: &'a G, k: f32) -> Segmentation
-where
-    G: GraphBase + Data<EdgeWeight = f32> + NodeIndexable,
-    &'a G: GraphBase<NodeId = G::NodeId> + IntoEdgeReferences + Data<EdgeWeight = f32>,
-{
-    // Component storage, and also mapping node indexes to components.
-    let mut components = vec![
-        ComponentSlot::Here(Component {
-            int_diff: 0.0,
-            node_count: 1
-        });
-        graph.node_bound()
-    ];
-
-    // To make merging easier, a component slot may point to another index via
-    // `ComponentSlot::There`. To find the component that a node currently
-    // belongs to, just follow the indexes until an instance of
-    // `ComponentSlot::Here` is found.
-    fn get_component(components: &[ComponentSlot], mut idx: usize) -> (usize, &Component) {
+/// Edge weight for a [`Segmentation::region_adjacency_graph`]: the minimum
+/// pixel-level boundary edge weight found between the two regions, and how
+/// many pixel-level edges make up that boundary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegionEdge {
+    pub weight: f32,
+    pub boundary_len: usize,
+}
+
+/// A coarser graph over a [`Segmentation`]'s output components, built by
+/// [`Segmentation::region_adjacency_graph`] and consumed by [`merge_regions`].
+pub type RegionGraph = UnGraph<Component, RegionEdge>;
+
+/// The result of re-merging a [`RegionGraph`]'s nodes with [`merge_regions`].
+///
+/// This is a distinct type from [`Segmentation`] because its indexing is one
+/// level removed from pixel space: `region_components` maps a *region*
+/// index (a node of the `RegionGraph`, i.e. a component index into the
+/// original [`Segmentation`]) to a merged-region index, not a pixel-graph
+/// node to one. Use [`RegionSegmentation::to_pixel_segmentation`] to compose
+/// it with the original `Segmentation` and get back something indexed by
+/// pixel-graph node, suitable for `label_image` or `enforce_min_size`.
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegionSegmentation {
+    /// Map from region indexes (as in the `Segmentation` the `RegionGraph`
+    /// was built from) to merged-region indexes.
+    pub region_components: Vec<usize>,
+
+    /// Component metadata from the re-merge.
+    pub components: Vec<Component>,
+}
+
+impl RegionSegmentation {
+    /// Compose with `original`, the `Segmentation` whose
+    /// [`Segmentation::region_adjacency_graph`] produced the input
+    /// `RegionGraph`, resolving region indexes back to pixel-graph node
+    /// indexes.
+    pub fn to_pixel_segmentation(&self, original: &Segmentation) -> Segmentation {
+        Segmentation {
+            node_components: original
+                .node_components
+                .iter()
+                .map(|&region| self.region_components[region])
+                .collect(),
+            components: self.components.clone(),
+        }
+    }
+}
+
+impl Segmentation {
+    /// Build a region adjacency graph: one node per output component,
+    /// carrying its [`Component`] metadata, with an edge between any two
+    /// components connected by at least one boundary edge in `graph` (the
+    /// same graph `segment` or `segment_by_key` was run on to produce this
+    /// `Segmentation`).
+    pub fn region_adjacency_graph<'a, G>(&self, graph: &'a G) -> RegionGraph
+    where
+        G: GraphBase + NodeIndexable,
+        &'a G: GraphBase<NodeId = G::NodeId> + IntoEdgeReferences + Data<EdgeWeight = f32>,
+    {
+        let mut rag = UnGraph::with_capacity(self.components.len(), 0);
+        let node_indices: Vec<NodeIndex> = self
+            .components
+            .iter()
+            .map(|component| rag.add_node(component.clone()))
+            .collect();
+
+        let mut boundaries: HashMap<(usize, usize), RegionEdge> = HashMap::new();
+        for edge in graph.edge_references() {
+            let c1 = self.node_components[graph.to_index(edge.source())];
+            let c2 = self.node_components[graph.to_index(edge.target())];
+            if c1 == c2 {
+                continue;
+            }
+
+            let key = (c1.min(c2), c1.max(c2));
+            let boundary = boundaries.entry(key).or_insert(RegionEdge {
+                weight: f32::INFINITY,
+                boundary_len: 0,
+            });
+            boundary.weight = boundary.weight.min(*edge.weight());
+            boundary.boundary_len += 1;
+        }
+
+        for ((c1, c2), boundary) in boundaries {
+            rag.add_edge(node_indices[c1], node_indices[c2], boundary);
+        }
+
+        rag
+    }
+
+    /// Render `node_components` back into an indexed label image, one `u16`
+    /// component index per pixel, so a segmentation can be saved, diffed, or
+    /// reloaded without rerunning `segment`. `graph` must be the same graph
+    /// this `Segmentation` was produced from, and `width`/`height` its
+    /// dimensions.
+    pub fn label_image<G>(&self, graph: &G, width: u32, height: u32) -> ImageBuffer<Luma<u16>, Vec<u16>>
+    where
+        G: GraphBase<NodeId = PixelCoordinate> + NodeIndexable,
+    {
+        let mut labels = ImageBuffer::new(width, height);
+        for (i, &component) in self.node_components.iter().enumerate() {
+            let PixelCoordinate { x, y } = graph.from_index(i);
+            labels.put_pixel(x, y, Luma([component as u16]));
+        }
+        labels
+    }
+
+    /// Merge any component smaller than `min_size` into the neighbor across
+    /// its smallest boundary edge, smallest undersized component first,
+    /// repeating until no undersized component with a remaining neighbor is
+    /// left. This is the standard FH cleanup pass for removing speckle from
+    /// noisy images; `graph` must be the same graph this `Segmentation` was
+    /// produced from.
+    pub fn enforce_min_size<'a, G>(&self, min_size: usize, graph: &'a G) -> Segmentation
+    where
+        G: GraphBase + NodeIndexable,
+        &'a G: GraphBase<NodeId = G::NodeId> + IntoEdgeReferences + Data<EdgeWeight = f32>,
+    {
+        struct Region {
+            component: Component,
+            // Neighbor region index -> minimum boundary edge weight to it.
+            neighbors: HashMap<usize, f32>,
+        }
+
+        let rag = self.region_adjacency_graph(graph);
+
+        let mut regions: Vec<Option<Region>> = rag
+            .node_weights()
+            .map(|component| {
+                Some(Region {
+                    component: component.clone(),
+                    neighbors: HashMap::new(),
+                })
+            })
+            .collect();
+        for edge in rag.edge_references() {
+            let a = edge.source().index();
+            let b = edge.target().index();
+            let weight = edge.weight().weight;
+            insert_min(&mut regions[a].as_mut().unwrap().neighbors, b, weight);
+            insert_min(&mut regions[b].as_mut().unwrap().neighbors, a, weight);
+        }
+
+        // Where a merged-away region index now lives, so pixel labels can be
+        // resolved to a surviving region after the loop below.
+        let mut redirect: Vec<usize> = (0..regions.len()).collect();
+
         loop {
-            match &components[idx] {
-                ComponentSlot::Here(component) => {
-                    break (idx, component);
+            let victim = regions
+                .iter()
+                .enumerate()
+                .filter(|(_, region)| {
+                    region
+                        .as_ref()
+                        .is_some_and(|r| r.component.node_count < min_size && !r.neighbors.is_empty())
+                })
+                .min_by_key(|(_, region)| region.as_ref().unwrap().component.node_count)
+                .map(|(i, _)| i);
+            let Some(i) = victim else {
+                break;
+            };
+
+            let (&j, _) = regions[i]
+                .as_ref()
+                .unwrap()
+                .neighbors
+                .iter()
+                .min_by(|a, b| a.1.partial_cmp(b.1).expect("NaN encountered in edge weights"))
+                .unwrap();
+
+            let absorbed = regions[i].take().unwrap();
+            redirect[i] = j;
+            {
+                let target = regions[j].as_mut().unwrap();
+                target.component.node_count += absorbed.component.node_count;
+                target.component.int_diff = target.component.int_diff.max(absorbed.component.int_diff);
+                target.neighbors.remove(&i);
+            }
+            for (neighbor, weight) in absorbed.neighbors {
+                if neighbor == j {
+                    continue;
+                }
+                if let Some(neighbor_region) = regions[neighbor].as_mut() {
+                    neighbor_region.neighbors.remove(&i);
+                    insert_min(&mut neighbor_region.neighbors, j, weight);
                 }
-                ComponentSlot::There(new_idx) => {
-                    idx = *new_idx;
+                if let Some(target) = regions[j].as_mut() {
+                    insert_min(&mut target.neighbors, neighbor, weight);
                 }
             }
         }
+
+        let mut remap: HashMap<usize, usize> = HashMap::new();
+        let mut out_components = Vec::new();
+        for (i, region) in regions.into_iter().enumerate() {
+            if let Some(region) = region {
+                remap.insert(i, out_components.len());
+                out_components.push(region.component);
+            }
+        }
+
+        let node_components = self
+            .node_components
+            .iter()
+            .map(|&component| {
+                let mut root = component;
+                while redirect[root] != root {
+                    root = redirect[root];
+                }
+                remap[&root]
+            })
+            .collect();
+
+        Segmentation {
+            node_components,
+            components: out_components,
+        }
     }
+}
 
+fn insert_min(map: &mut HashMap<usize, f32>, key: usize, value: f32) {
+    map.entry(key)
+        .and_modify(|existing| *existing = existing.min(value))
+        .or_insert(value);
+}
+
+/// Agglomerate a [`RegionGraph`] with the same FH merge criterion `segment`
+/// uses, producing coarser regions without revisiting the original
+/// pixel-level edges.
+pub fn merge_regions(rag: &RegionGraph, k2: f32) -> RegionSegmentation {
+    let mut queue: Vec<_> = rag.edge_references().collect();
+    queue.sort_by(|a, b| {
+        a.weight()
+            .weight
+            .partial_cmp(&b.weight().weight)
+            .expect("NaN encountered in edge weights")
+    });
+
+    let merged = merge_components(
+        rag,
+        rag.node_weights().cloned().collect::<Vec<_>>().into_iter(),
+        k2,
+        queue
+            .into_iter()
+            .map(|edge| (edge.source(), edge.target(), edge.weight().weight)),
+        default_threshold,
+    );
+
+    RegionSegmentation {
+        region_components: merged.node_components,
+        components: merged.components,
+    }
+}
+
+/// The merge criterion from the original FH paper: a component tolerates an
+/// outgoing edge up to `k` over its size above its internal difference.
+/// This is the default passed by [`segment`]; swap in a different function
+/// via [`segment_with`] (e.g. `k / node_count.sqrt()` for a less aggressive
+/// falloff, or a constant for plain single-linkage clustering).
+pub fn default_threshold(component: &Component, k: f32) -> f32 {
+    component.int_diff + k / component.node_count as f32
+}
+
+pub fn segment<'a, G>(graph: &'a G, k: f32) -> Segmentation
+where
+    G: GraphBase + Data<EdgeWeight = f32> + NodeIndexable,
+    &'a G: GraphBase<NodeId = G::NodeId> + IntoEdgeReferences + Data<EdgeWeight = f32>,
+{
+    segment_with(graph, k, default_threshold)
+}
+
+/// Like [`segment`], but with the merge threshold as an explicit parameter
+/// instead of hard-coding [`default_threshold`].
+pub fn segment_with<'a, G, T>(graph: &'a G, k: f32, threshold: T) -> Segmentation
+where
+    G: GraphBase + Data<EdgeWeight = f32> + NodeIndexable,
+    &'a G: GraphBase<NodeId = G::NodeId> + IntoEdgeReferences + Data<EdgeWeight = f32>,
+    T: Fn(&Component, f32) -> f32,
+{
     // Sort E by non-decreasing edge weight.
     let mut queue: Vec<_> = graph.edge_references().collect();
     queue.sort_by(|a, b| {
@@ -76,13 +333,100 @@ where
             .expect("NaN encountered in edge weights")
     });
 
-    for edge in queue {
+    merge_components(
+        graph,
+        (0..graph.node_bound()).map(|_| Component {
+            int_diff: 0.0,
+            node_count: 1,
+        }),
+        k,
+        queue
+            .into_iter()
+            .map(|edge| (edge.source(), edge.target(), *edge.weight())),
+        threshold,
+    )
+}
+
+/// Like [`segment`], but takes a closure mapping each edge to a `u16` bucket
+/// instead of requiring `EdgeWeight = f32`, and sorts the edge queue with a
+/// counting sort in O(E + W) rather than the comparison sort in O(E log E)
+/// that `segment` uses.
+///
+/// This is the fast path for pipelines like `gbis`, where edge weights are
+/// intensity differences of `Luma<u8>` pixels and so fall in a small,
+/// bounded integer range.
+pub fn segment_by_key<'a, G, W>(graph: &'a G, k: f32, weight_fn: W) -> Segmentation
+where
+    G: GraphBase + NodeIndexable,
+    &'a G: GraphBase<NodeId = G::NodeId> + IntoEdgeReferences,
+    W: Fn(&<&'a G as IntoEdgeReferences>::EdgeRef) -> u16,
+{
+    let edges: Vec<_> = graph.edge_references().collect();
+    let keys: Vec<u16> = edges.iter().map(&weight_fn).collect();
+    let sorted_indices = counting_sort_indices(&keys);
+
+    merge_components(
+        graph,
+        (0..graph.node_bound()).map(|_| Component {
+            int_diff: 0.0,
+            node_count: 1,
+        }),
+        k,
+        sorted_indices
+            .into_iter()
+            .map(|i| (edges[i].source(), edges[i].target(), keys[i] as f32)),
+        default_threshold,
+    )
+}
+
+/// Bucket `keys` into `0..=u16::MAX` and return their indexes in
+/// non-decreasing key order, computed with a single counting pass, a
+/// prefix sum over bucket counts, and one scatter into a flat `Vec` (rather
+/// than allocating a `Vec` per bucket).
+fn counting_sort_indices(keys: &[u16]) -> Vec<usize> {
+    const NUM_BUCKETS: usize = u16::MAX as usize + 1;
+
+    let mut counts = [0usize; NUM_BUCKETS];
+    for &key in keys {
+        counts[key as usize] += 1;
+    }
+
+    let mut offsets = [0usize; NUM_BUCKETS];
+    let mut running = 0;
+    for (bucket, &count) in counts.iter().enumerate() {
+        offsets[bucket] = running;
+        running += count;
+    }
+
+    let mut cursor = offsets;
+    let mut sorted_indices = vec![0usize; keys.len()];
+    for (i, &key) in keys.iter().enumerate() {
+        sorted_indices[cursor[key as usize]] = i;
+        cursor[key as usize] += 1;
+    }
+
+    sorted_indices
+}
+
+/// Run the Felzenszwalb-Huttenlocher merge loop over edges already sorted
+/// by non-decreasing weight, using a [`DisjointComponents`] union-find so
+/// merges stay near-constant time.
+fn merge_components<N>(
+    graph: &impl NodeIndexable<NodeId = N>,
+    initial_components: impl ExactSizeIterator<Item = Component>,
+    k: f32,
+    sorted_edges: impl IntoIterator<Item = (N, N, f32)>,
+    threshold: impl Fn(&Component, f32) -> f32,
+) -> Segmentation {
+    let mut components = DisjointComponents::new(initial_components);
+
+    for (source, target, weight) in sorted_edges {
         // Let v1 and v2 denote the vertices connected by the edge.
-        let v1_idx = graph.to_index(edge.source());
-        let v2_idx = graph.to_index(edge.target());
+        let v1_idx = graph.to_index(source);
+        let v2_idx = graph.to_index(target);
 
-        let (c1_idx, c1) = get_component(&components, v1_idx);
-        let (c2_idx, c2) = get_component(&components, v2_idx);
+        let c1_idx = components.find(v1_idx);
+        let c2_idx = components.find(v2_idx);
 
         // If v1 and v2 are in disjoint components and the edge weight is small
         // compared to the internal difference of both components, then merge
@@ -90,47 +434,24 @@ where
         if c1_idx == c2_idx {
             continue;
         }
-        // TODO customizable threshold function
-        let mint = f32::min(
-            c1.int_diff + k / (c1.node_count as f32),
-            c2.int_diff + k / (c2.node_count as f32),
-        );
-        if *edge.weight() > mint {
+        let c1 = components.component(c1_idx);
+        let c2 = components.component(c2_idx);
+        let mint = f32::min(threshold(c1, k), threshold(c2, k));
+        if weight > mint {
             continue;
         }
 
-        // Merge the components (c2 into c1).
         let new_component = Component {
-            int_diff: c1.int_diff.max(c2.int_diff).max(*edge.weight()),
+            int_diff: c1.int_diff.max(c2.int_diff).max(weight),
             node_count: c1.node_count + c2.node_count,
         };
-        components[c1_idx] = ComponentSlot::Here(new_component);
-        components[c2_idx] = ComponentSlot::There(c1_idx);
+        components.union(c1_idx, c2_idx, new_component);
     }
 
-    // Gather the remaining components and re-index them.
-    let mut component_map: Vec<Option<usize>> = vec![None; components.len()];
-
-    let out_components: Vec<Component> = components
-        .iter()
-        .enumerate()
-        .filter_map(|(i, slot)| match slot {
-            ComponentSlot::Here(component) => Some((i, component)),
-            ComponentSlot::There(_) => None,
-        })
-        .enumerate()
-        .map(|(i_dst, (i_src, component))| {
-            component_map[i_src] = Some(i_dst);
-            component.clone()
-        })
-        .collect();
-
-    let out_node_components = (0..graph.node_bound())
-        .map(|node_idx| component_map[get_component(&components, node_idx).0].unwrap())
-        .collect();
+    let (node_components, out_components) = components.finish();
 
     Segmentation {
-        node_components: out_node_components,
+        node_components,
         components: out_components,
     }
 }
@@ -153,3 +474,59 @@ where
     }
     components
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petgraph::graph::UnGraph;
+
+    #[test]
+    fn counting_sort_indices_is_stable_and_non_decreasing() {
+        let keys = [3u16, 1, 2, 1, 0];
+        let sorted = counting_sort_indices(&keys);
+
+        let sorted_keys: Vec<u16> = sorted.iter().map(|&i| keys[i]).collect();
+        assert_eq!(sorted_keys, [0, 1, 1, 2, 3]);
+        // Indexes 1 and 3 are tied at key 1; a stable sort keeps them in
+        // their original relative order.
+        assert_eq!((sorted[1], sorted[2]), (1, 3));
+    }
+
+    #[test]
+    fn enforce_min_size_merges_only_undersized_regions() {
+        // a - b - c, with a and b undersized and c already large enough.
+        let mut graph = UnGraph::<(), f32>::new_undirected();
+        let a = graph.add_node(());
+        let b = graph.add_node(());
+        let c = graph.add_node(());
+        graph.add_edge(a, b, 0.1);
+        graph.add_edge(b, c, 0.9);
+
+        let segmentation = Segmentation {
+            node_components: vec![0, 1, 2],
+            components: vec![
+                Component {
+                    int_diff: 0.0,
+                    node_count: 1,
+                },
+                Component {
+                    int_diff: 0.0,
+                    node_count: 1,
+                },
+                Component {
+                    int_diff: 0.0,
+                    node_count: 5,
+                },
+            ],
+        };
+
+        let merged = segmentation.enforce_min_size(2, &graph);
+
+        // `a` only has `b` as a neighbor, so it must merge there rather
+        // than leapfrogging to `c`; `c` is already at the minimum size and
+        // is left alone.
+        assert_eq!(merged.node_components[0], merged.node_components[1]);
+        assert_ne!(merged.node_components[0], merged.node_components[2]);
+        assert_eq!(merged.components.len(), 2);
+    }
+}
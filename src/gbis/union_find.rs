@@ -0,0 +1,153 @@
+//! A disjoint-set (union-find) structure specialized for [`segment`](super::segment):
+//! each root additionally carries the [`Component`] metadata the FH merge
+//! criterion needs, `find` does path compression, and `union` always
+//! attaches the smaller tree under the larger so lookups stay cheap even on
+//! adversarial merge orders.
+
+use super::Component;
+
+struct Node {
+    parent: usize,
+    size: usize,
+}
+
+/// Union-find over components, indexed by the same node indexes as the
+/// graph being segmented.
+pub struct DisjointComponents {
+    nodes: Vec<Node>,
+    components: Vec<Option<Component>>,
+}
+
+impl DisjointComponents {
+    /// Start with every node in its own singleton component.
+    pub fn new(initial: impl ExactSizeIterator<Item = Component>) -> Self {
+        let components: Vec<Option<Component>> = initial.map(Some).collect();
+        let nodes = (0..components.len())
+            .map(|i| Node { parent: i, size: 1 })
+            .collect();
+        Self { nodes, components }
+    }
+
+    /// Find the root index for `idx`'s component, compressing the path
+    /// along the way so future lookups through `idx` are O(1).
+    pub fn find(&mut self, idx: usize) -> usize {
+        let mut root = idx;
+        while self.nodes[root].parent != root {
+            root = self.nodes[root].parent;
+        }
+
+        let mut cur = idx;
+        while self.nodes[cur].parent != root {
+            let next = self.nodes[cur].parent;
+            self.nodes[cur].parent = root;
+            cur = next;
+        }
+
+        root
+    }
+
+    /// The metadata for the component rooted at `root`.
+    ///
+    /// Panics if `root` isn't currently a root (i.e. wasn't the return value
+    /// of [`DisjointComponents::find`] or [`DisjointComponents::union`]).
+    pub fn component(&self, root: usize) -> &Component {
+        self.components[root]
+            .as_ref()
+            .expect("index is not a component root")
+    }
+
+    /// Merge the components rooted at `a` and `b` (as returned by `find`),
+    /// replacing the surviving root's metadata with `merged`, and return the
+    /// surviving root index.
+    pub fn union(&mut self, mut a: usize, mut b: usize, merged: Component) -> usize {
+        debug_assert_ne!(a, b, "cannot union a component with itself");
+
+        // Union by size: attach the smaller tree under the larger so no
+        // single `find` chain can grow past O(log n) even before path
+        // compression kicks in.
+        if self.nodes[a].size < self.nodes[b].size {
+            std::mem::swap(&mut a, &mut b);
+        }
+
+        self.nodes[b].parent = a;
+        self.nodes[a].size += self.nodes[b].size;
+        self.components[b] = None;
+        self.components[a] = Some(merged);
+        a
+    }
+
+    /// Consume the structure, returning a node-to-component-index map
+    /// (re-indexed to `0..components.len()`) and the surviving components.
+    pub fn finish(mut self) -> (Vec<usize>, Vec<Component>) {
+        let mut remap: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut out_components = Vec::new();
+
+        for i in 0..self.nodes.len() {
+            if let Some(component) = self.components[i].take() {
+                remap[i] = Some(out_components.len());
+                out_components.push(component);
+            }
+        }
+
+        let node_components = (0..self.nodes.len())
+            .map(|i| remap[self.find(i)].expect("find() must resolve to a surviving root"))
+            .collect();
+
+        (node_components, out_components)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn singleton() -> Component {
+        Component {
+            int_diff: 0.0,
+            node_count: 1,
+        }
+    }
+
+    #[test]
+    fn find_is_identity_before_any_union() {
+        let mut components = DisjointComponents::new((0..4).map(|_| singleton()));
+        for i in 0..4 {
+            assert_eq!(components.find(i), i);
+        }
+    }
+
+    #[test]
+    fn union_attaches_smaller_tree_under_larger_root() {
+        let mut components = DisjointComponents::new((0..4).map(|_| singleton()));
+
+        // 0..1 is a two-node tree; 2 is a singleton, so unioning them should
+        // attach 2 under 0's root rather than the other way around.
+        let merged = Component {
+            int_diff: 0.4,
+            node_count: 2,
+        };
+        let root01 = components.union(0, 1, singleton());
+        let root012 = components.union(root01, 2, merged);
+
+        assert_eq!(root012, root01);
+        assert_eq!(components.find(0), root012);
+        assert_eq!(components.find(1), root012);
+        assert_eq!(components.find(2), root012);
+        assert_eq!(components.find(3), 3);
+        assert_eq!(components.component(root012).node_count, 2);
+    }
+
+    #[test]
+    fn finish_remaps_nodes_to_dense_surviving_component_indexes() {
+        let mut components = DisjointComponents::new((0..4).map(|_| singleton()));
+        components.union(0, 1, singleton());
+
+        let (node_components, out_components) = components.finish();
+
+        assert_eq!(out_components.len(), 3);
+        assert_eq!(node_components[0], node_components[1]);
+        assert_ne!(node_components[0], node_components[2]);
+        assert_ne!(node_components[0], node_components[3]);
+        assert_ne!(node_components[2], node_components[3]);
+    }
+}
@@ -1,4 +1,10 @@
+pub mod autocrop;
 pub mod config;
+pub mod db;
+pub mod gbis;
+pub mod preview;
+pub mod scratch;
+pub mod search;
 pub mod tmdb;
 
 use std::num::NonZeroU32;
@@ -24,6 +30,22 @@ impl Hash {
             .map(|(x, y)| (x ^ y).count_ones())
             .sum()
     }
+
+    /// The raw 8 bytes backing this hash, for storage in a binary column.
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.0
+    }
+
+    /// Reconstruct a hash from bytes previously returned by [`Hash::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, std::array::TryFromSliceError> {
+        RawHash::try_from(bytes).map(Hash)
+    }
+
+    /// Pack the hash into a single `u64` lane for the SIMD search kernels in
+    /// [`crate::search`].
+    pub(crate) fn to_lane(self) -> u64 {
+        u64::from_le_bytes(self.0)
+    }
 }
 
 impl Serialize for Hash {
@@ -130,6 +152,126 @@ impl GradientHash {
     }
 }
 
+/// Classic pHash: resize to 32x32, run a separable 2-D DCT-II, and threshold
+/// the low-frequency coefficients against their median.
+///
+/// Unlike [`MeanHash`] and [`GradientHash`], which compare raw pixel
+/// intensities, `DctHash` compares frequency-domain coefficients, which
+/// makes it far less sensitive to the blur, gamma shifts, and re-encoding
+/// artifacts that broadcast rips go through before being compared against
+/// clean TMDB stills.
+pub struct DctHash {
+    resizer: Resizer,
+    resized_image: GrayImage,
+}
+
+impl DctHash {
+    const SIZE: u32 = 32;
+    const LOW_FREQ: u32 = 8;
+
+    pub fn new() -> Self {
+        Self {
+            resizer: Resizer::new(),
+            resized_image: GrayImage::new(Self::SIZE, Self::SIZE),
+        }
+    }
+
+    pub fn hash(&mut self, image: &GrayImage) -> Hash {
+        self.resizer.resize(image, &mut self.resized_image);
+        let mut raw_hash = RawHash::default();
+        collect_bits(dct_hash(&self.resized_image).into_iter(), &mut raw_hash);
+        Hash(raw_hash)
+    }
+}
+
+/// Enumerates the hash algorithms exposed to the CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HashMode {
+    Mean,
+    Gradient,
+    Dct,
+}
+
+/// A hasher chosen at runtime by [`HashMode`], so callers don't need to
+/// monomorphize over every hash algorithm.
+pub enum AnyHasher {
+    Mean(MeanHash),
+    Gradient(GradientHash),
+    Dct(DctHash),
+}
+
+impl AnyHasher {
+    pub fn new(mode: HashMode) -> Self {
+        match mode {
+            HashMode::Mean => AnyHasher::Mean(MeanHash::new()),
+            HashMode::Gradient => AnyHasher::Gradient(GradientHash::new()),
+            HashMode::Dct => AnyHasher::Dct(DctHash::new()),
+        }
+    }
+
+    pub fn hash(&mut self, image: &GrayImage) -> Hash {
+        match self {
+            AnyHasher::Mean(hasher) => hasher.hash(image),
+            AnyHasher::Gradient(hasher) => hasher.hash(image),
+            AnyHasher::Dct(hasher) => hasher.hash(image),
+        }
+    }
+}
+
+/// Compute a 1-D DCT-II of `input`, producing `input.len()` coefficients.
+fn dct_1d(input: &[f32]) -> Vec<f32> {
+    let n = input.len();
+    (0..n)
+        .map(|k| {
+            input
+                .iter()
+                .enumerate()
+                .map(|(x, &v)| {
+                    v * (std::f32::consts::PI / n as f32 * (x as f32 + 0.5) * k as f32).cos()
+                })
+                .sum()
+        })
+        .collect()
+}
+
+fn dct_hash(luma: &GrayImage) -> [bool; 64] {
+    let n = DctHash::SIZE as usize;
+    let low_freq = DctHash::LOW_FREQ as usize;
+
+    // DCT each row, then DCT each column of the result (separable 2-D DCT).
+    let mut rows = vec![0f32; n * n];
+    for (y, row) in luma.as_raw().chunks(n).enumerate() {
+        let pixels: Vec<f32> = row.iter().map(|&p| p as f32).collect();
+        rows[y * n..][..n].copy_from_slice(&dct_1d(&pixels));
+    }
+
+    let mut coefficients = vec![0f32; n * n];
+    for x in 0..n {
+        let column: Vec<f32> = (0..n).map(|y| rows[y * n + x]).collect();
+        let dct_column = dct_1d(&column);
+        for (y, &value) in dct_column.iter().enumerate() {
+            coefficients[y * n + x] = value;
+        }
+    }
+
+    // Keep only the top-left (lowest-frequency) block.
+    let low_freq_coefficients: Vec<f32> = (0..low_freq)
+        .flat_map(|y| coefficients[y * n..][..low_freq].iter().copied())
+        .collect();
+
+    // The median excludes the [0, 0] DC term, which only encodes overall
+    // brightness and would otherwise skew the threshold.
+    let mut sorted = low_freq_coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("NaN encountered in DCT output"));
+    let median = sorted[sorted.len() / 2];
+
+    let mut bits = [false; 64];
+    for (bit, &coefficient) in bits.iter_mut().zip(&low_freq_coefficients) {
+        *bit = coefficient > median;
+    }
+    bits
+}
+
 fn mean_hash(luma: &[u8]) -> impl Iterator<Item = bool> + '_ {
     let mean = luma.iter().map(|&l| l as f32).sum::<f32>() / luma.len() as f32;
     luma.iter().map(move |&l| l as f32 > mean)
@@ -5,13 +5,18 @@ use image::GrayImage;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::HashMap,
     fs::File,
     io::{stdout, BufReader, BufWriter, Read},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::mpsc,
+};
+use tvid::{
+    db::{Db, Still},
+    scratch::{ScratchReader, ScratchWriter},
+    tmdb::Tmdb,
+    AnyHasher, GradientHash, Hash, HashMode,
 };
-use tvid::{tmdb::Tmdb, GradientHash, Hash};
 
 #[derive(Debug, clap::Parser)]
 struct Args {
@@ -46,7 +51,25 @@ enum Command {
     Fetch(FetchArgs),
     Hash(HashArgs),
     Identify(IdentifyArgs),
-    Compare { tvid: PathBuf, image: PathBuf },
+    Compare {
+        tvid: PathBuf,
+        image: PathBuf,
+
+        /// Render the query image and the best-matching frame inline in the
+        /// terminal alongside the match results.
+        ///
+        /// Requires `--scratch` pointing at the `--reuse` cache the `hash`
+        /// run that produced `tvid` was decoded into, since that's the only
+        /// place the matched frame's pixels still exist.
+        #[clap(long, requires = "scratch")]
+        preview: bool,
+
+        /// Path to the decoded-frame scratch cache `hash` wrote while
+        /// producing `tvid`, used to render the matched frame for
+        /// `--preview`.
+        #[clap(long)]
+        scratch: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -65,6 +88,13 @@ struct SearchArgs {
 struct FetchArgs {
     tvid: i32,
     season: i32,
+
+    /// Path to the reference library database.
+    #[clap(short, long, default_value = "tvid.db")]
+    db: PathBuf,
+
+    #[clap(long, value_enum, default_value = "gradient")]
+    hash_mode: HashMode,
 }
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -74,12 +104,37 @@ struct HashArgs {
     crop_aspect: Option<Aspect>,
     #[clap(short, long)]
     output: Option<PathBuf>,
+
+    /// Automatically detect and crop out letterbox/pillarbox bars instead of
+    /// cropping to a fixed `--crop-aspect`.
+    #[clap(long, conflicts_with = "crop_aspect")]
+    autocrop: bool,
+
+    /// Stream previously-decoded frames back from the scratch cache instead
+    /// of re-running ffmpeg.
+    #[clap(long)]
+    reuse: bool,
+
+    /// Path to the decoded-frame scratch cache.
+    ///
+    /// Defaults to the video path with its extension replaced.
+    #[clap(long)]
+    scratch: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value = "gradient")]
+    hash_mode: HashMode,
 }
 
 #[derive(Debug, Clone, clap::Parser)]
 struct IdentifyArgs {
     tvid: PathBuf,
-    tvds: PathBuf,
+
+    tv_id: i32,
+    season: i32,
+
+    /// Path to the reference library database.
+    #[clap(short, long, default_value = "tvid.db")]
+    db: PathBuf,
 
     // Maximum episode number
     #[clap(short('m'), long)]
@@ -102,7 +157,12 @@ fn main() -> anyhow::Result<()> {
         Command::Fetch(fetch_args) => fetch(&config, fetch_args),
         Command::Hash(hash_args) => hash(hash_args),
         Command::Identify(identify_args) => identify(identify_args),
-        Command::Compare { tvid, image } => compare(tvid, image),
+        Command::Compare {
+            tvid,
+            image,
+            preview,
+            scratch,
+        } => compare(tvid, image, *preview, scratch.as_deref()),
     }
 }
 
@@ -110,6 +170,7 @@ fn main() -> anyhow::Result<()> {
 struct IdResult {
     mse: u32,
     episode: i32,
+    timestamp_ms: i64,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -117,21 +178,26 @@ struct CompareResult {
     distance: u32,
     frame: u64,
     hash: Hash,
+    timestamp_ms: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Tvid {
     hashes: Vec<Hash>,
+    /// Each frame's presentation timestamp, in milliseconds, parallel to
+    /// `hashes`.
+    timestamps: Vec<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Tvds {
-    episodes: HashMap<i32, Episode>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Episode {
-    thumbnails: Vec<Hash>,
+/// Format a millisecond timestamp as `HH:MM:SS.mmm`.
+fn format_timecode(ms: i64) -> String {
+    let millis = ms.rem_euclid(1000);
+    let total_secs = ms.div_euclid(1000);
+    let secs = total_secs.rem_euclid(60);
+    let total_mins = total_secs.div_euclid(60);
+    let mins = total_mins.rem_euclid(60);
+    let hours = total_mins.div_euclid(60);
+    format!("{hours:02}:{mins:02}:{secs:02}.{millis:03}")
 }
 
 fn search(config: &tvid::config::Config, search_args: &SearchArgs) -> anyhow::Result<()> {
@@ -149,58 +215,104 @@ fn search(config: &tvid::config::Config, search_args: &SearchArgs) -> anyhow::Re
 
 fn fetch(config: &tvid::config::Config, fetch_args: &FetchArgs) -> anyhow::Result<()> {
     let mut tmdb = Tmdb::new(config);
+    let mut db = Db::open(&fetch_args.db)?;
 
     let season = tmdb.season_details(fetch_args.tvid, fetch_args.season)?;
 
-    let mut hasher = GradientHash::new();
+    let mut hasher = AnyHasher::new(fetch_args.hash_mode);
 
-    let tvds = Tvds {
-        episodes: season
-            .episodes
+    for ep in season.episodes {
+        let stills: Vec<Still> = tmdb
+            .episode_images(fetch_args.tvid, fetch_args.season, ep.episode_number)?
+            .stills
             .into_iter()
-            .map(|ep| {
-                Ok((
-                    ep.episode_number,
-                    Episode {
-                        thumbnails: tmdb
-                            .episode_images(fetch_args.tvid, fetch_args.season, ep.episode_number)?
-                            .stills
-                            .into_iter()
-                            .map(|image_ref| -> anyhow::Result<Hash> {
-                                let mut image_data = Vec::new();
-                                let image_reader = tmdb.get_image(&image_ref.file_path)?;
-                                image_reader.take(1 << 30).read_to_end(&mut image_data)?;
-                                let image = image::load_from_memory(&image_data)?;
-                                let gray_image = image.into_luma8();
-
-                                Ok(hasher.hash(&gray_image))
-                            })
-                            .flat_map(|result| match result {
-                                Ok(x) => Some(x),
-                                Err(e) => {
-                                    eprintln!("error loading image: {:?}", e);
-                                    None
-                                }
-                            })
-                            .collect(),
-                    },
-                ))
+            .map(|image_ref| -> anyhow::Result<Still> {
+                let mut image_data = Vec::new();
+                let image_reader = tmdb.get_image(&image_ref.file_path)?;
+                image_reader.take(1 << 30).read_to_end(&mut image_data)?;
+                let image = image::load_from_memory(&image_data)?;
+                let gray_image = image.into_luma8();
+
+                Ok(Still {
+                    file_path: image_ref.file_path,
+                    width: gray_image.width(),
+                    height: gray_image.height(),
+                    hash: hasher.hash(&gray_image),
+                })
             })
-            .collect::<anyhow::Result<_>>()?,
-    };
-
-    serde_json::to_writer(
-        BufWriter::new(File::create(format!(
-            "{}s{:02}.tvds",
-            fetch_args.tvid, fetch_args.season
-        ))?),
-        &tvds,
-    )?;
+            .flat_map(|result| match result {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    eprintln!("error loading image: {:?}", e);
+                    None
+                }
+            })
+            .collect();
+
+        db.put_episode(
+            fetch_args.tvid,
+            fetch_args.season,
+            ep.episode_number,
+            &stills,
+        )?;
+    }
 
     Ok(())
 }
 
 fn hash(args: &HashArgs) -> anyhow::Result<()> {
+    let scratch_path = args
+        .scratch
+        .clone()
+        .unwrap_or_else(|| args.video.with_extension("tvid-scratch"));
+
+    // Bound the channel to a handful of frames so the decoder can't run
+    // arbitrarily far ahead of the hasher and blow up memory.
+    let (tx, rx) = mpsc::sync_channel::<(usize, i64, GrayImage)>(4);
+
+    let mut hasher = AnyHasher::new(args.hash_mode);
+    let mut frames = Vec::new();
+
+    std::thread::scope(|scope| -> anyhow::Result<()> {
+        let decoder_thread = scope.spawn(|| {
+            if args.reuse {
+                stream_cached_frames(&scratch_path, tx)
+            } else {
+                decode_and_cache_frames(args, &scratch_path, tx)
+            }
+        });
+
+        for (frame_index, timestamp_ms, gray_image) in rx {
+            frames.push((frame_index, timestamp_ms, hasher.hash(&gray_image)));
+        }
+
+        decoder_thread.join().expect("decoder thread panicked")
+    })?;
+
+    frames.sort_by_key(|(index, _, _)| *index);
+    let (timestamps, hashes) = frames
+        .into_iter()
+        .map(|(_, timestamp_ms, hash)| (timestamp_ms, hash))
+        .unzip();
+
+    let tvid = Tvid { hashes, timestamps };
+
+    match &args.output {
+        Some(path) => serde_json::to_writer(BufWriter::new(File::create(path)?), &tvid)?,
+        None => serde_json::to_writer(stdout(), &tvid)?,
+    }
+
+    Ok(())
+}
+
+/// Decode `args.video` with ffmpeg, cropping every frame down to the
+/// content region and sending it to the hasher while also appending it to
+/// the scratch cache for later reuse.
+fn decode_and_cache_frames(
+    args: &HashArgs,
+    scratch_path: &Path,
+    tx: mpsc::SyncSender<(usize, i64, GrayImage)>,
+) -> anyhow::Result<()> {
     ffmpeg::init().unwrap();
     let mut ictx = ffmpeg::format::input(&args.video)?;
     let input = ictx
@@ -208,6 +320,7 @@ fn hash(args: &HashArgs) -> anyhow::Result<()> {
         .best(ffmpeg::media::Type::Video)
         .ok_or(ffmpeg::Error::StreamNotFound)?;
     let video_stream_index = input.index();
+    let time_base = input.time_base();
 
     let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())?;
     let mut decoder = context_decoder.decoder().video()?;
@@ -222,63 +335,52 @@ fn hash(args: &HashArgs) -> anyhow::Result<()> {
         ffmpeg::software::scaling::flag::Flags::BILINEAR,
     )?;
 
-    let (crop_x, crop_y, crop_width, crop_height) = match args.crop_aspect.map(|aspect| {
-        (
-            aspect,
-            (decoder.width() * aspect.height).cmp(&(decoder.height() * aspect.width)),
-        )
-    }) {
-        Some((aspect, Ordering::Less)) => {
-            // Crop top and bottom
-            let target_height = decoder.width() * aspect.height / aspect.width;
-            (
-                0,
-                (decoder.height() - target_height) / 2,
-                decoder.width(),
-                target_height,
-            )
-        }
-        Some((aspect, Ordering::Greater)) => {
-            // Crop left and right
-            let target_width = decoder.height() * aspect.width / aspect.height;
-            (
-                (decoder.width() - target_width) / 2,
-                0,
-                target_width,
-                decoder.height(),
-            )
-        }
-        Some((_, Ordering::Equal)) | None => (0, 0, decoder.width(), decoder.height()),
-    };
+    let full_width = decoder.width();
+    let full_height = decoder.height();
 
-    let mut hasher = GradientHash::new();
+    // With `--autocrop`, the content region isn't known until the first
+    // frame has actually been decoded, so its computation (and the scratch
+    // cache it sizes) is deferred until then.
+    let mut crop_rect = if args.autocrop {
+        None
+    } else {
+        Some(static_crop_rect(args.crop_aspect, full_width, full_height))
+    };
+    let mut scratch: Option<ScratchWriter> = None;
 
     let mut frame_index = 0;
 
-    let mut hashes = Vec::new();
-
     let mut receive_and_process_decoded_frames =
-        |decoder: &mut ffmpeg::decoder::Video| -> Result<(), ffmpeg::Error> {
+        |decoder: &mut ffmpeg::decoder::Video| -> anyhow::Result<()> {
             let mut decoded = ffmpeg::util::frame::video::Video::empty();
             let mut gray_frame = ffmpeg::util::frame::video::Video::empty();
             while decoder.receive_frame(&mut decoded).is_ok() {
                 scaler.run(&decoded, &mut gray_frame)?;
 
-                let mut pack_and_crop = vec![0u8; (crop_width * crop_height) as usize];
-                let src_stride = gray_frame.stride(0) as usize;
-                let dest_stride = crop_width as usize;
-                for row in 0..crop_height as usize {
-                    pack_and_crop[row * dest_stride..][..dest_stride].copy_from_slice(
-                        &gray_frame.data(0)
-                            [(row + crop_y as usize) * src_stride + (crop_x as usize)..]
-                            [..dest_stride],
-                    );
-                }
-                let gray_image =
-                    GrayImage::from_raw(crop_width, crop_height, pack_and_crop).unwrap();
+                let (crop_x, crop_y, crop_width, crop_height) = *crop_rect.get_or_insert_with(|| {
+                    let full = pack_frame(&gray_frame, 0, 0, full_width, full_height);
+                    tvid::autocrop::detect_crop(&full)
+                });
+
+                let gray_image = pack_frame(&gray_frame, crop_x, crop_y, crop_width, crop_height);
+
+                let scratch = match &mut scratch {
+                    Some(scratch) => scratch,
+                    None => scratch
+                        .insert(ScratchWriter::create(scratch_path, crop_width, crop_height)?),
+                };
+
+                let timestamp_ms = decoded
+                    .pts()
+                    .map(|pts| pts * time_base.numerator() as i64 * 1000 / time_base.denominator() as i64)
+                    .unwrap_or(-1);
+
+                scratch.write_frame(timestamp_ms, gray_image.as_raw())?;
 
-                hashes.push(hasher.hash(&gray_image));
-                dbg!(frame_index);
+                // The hasher may have shut down (e.g. on an early error); in
+                // that case there's nothing left to do but keep draining
+                // ffmpeg's buffers.
+                let _ = tx.send((frame_index, timestamp_ms, gray_image));
                 frame_index += 1;
             }
             Ok(())
@@ -293,47 +395,94 @@ fn hash(args: &HashArgs) -> anyhow::Result<()> {
     decoder.send_eof()?;
     receive_and_process_decoded_frames(&mut decoder)?;
 
-    let tvid = Tvid { hashes };
+    Ok(())
+}
 
-    match &args.output {
-        Some(path) => serde_json::to_writer(BufWriter::new(File::create(path)?), &tvid)?,
-        None => serde_json::to_writer(stdout(), &tvid)?,
+/// Compute a fixed crop rectangle from a target aspect ratio, cropping top
+/// and bottom or left and right as needed. Used when `--autocrop` isn't
+/// given.
+fn static_crop_rect(crop_aspect: Option<Aspect>, width: u32, height: u32) -> (u32, u32, u32, u32) {
+    match crop_aspect.map(|aspect| (aspect, (width * aspect.height).cmp(&(height * aspect.width)))) {
+        Some((aspect, Ordering::Less)) => {
+            // Crop top and bottom
+            let target_height = width * aspect.height / aspect.width;
+            (0, (height - target_height) / 2, width, target_height)
+        }
+        Some((aspect, Ordering::Greater)) => {
+            // Crop left and right
+            let target_width = height * aspect.width / aspect.height;
+            ((width - target_width) / 2, 0, target_width, height)
+        }
+        Some((_, Ordering::Equal)) | None => (0, 0, width, height),
+    }
+}
+
+/// Pack one decoded GRAY8 ffmpeg frame's `(x, y, w, h)` region into a
+/// tightly-packed [`GrayImage`], respecting ffmpeg's row stride.
+fn pack_frame(
+    gray_frame: &ffmpeg::util::frame::video::Video,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+) -> GrayImage {
+    let mut buf = vec![0u8; (w * h) as usize];
+    let src_stride = gray_frame.stride(0) as usize;
+    let dest_stride = w as usize;
+    for row in 0..h as usize {
+        buf[row * dest_stride..][..dest_stride].copy_from_slice(
+            &gray_frame.data(0)[(row + y as usize) * src_stride + (x as usize)..][..dest_stride],
+        );
     }
+    GrayImage::from_raw(w, h, buf).unwrap()
+}
 
+/// Stream frames directly from a previously-written scratch cache, skipping
+/// ffmpeg entirely.
+fn stream_cached_frames(
+    scratch_path: &Path,
+    tx: mpsc::SyncSender<(usize, i64, GrayImage)>,
+) -> anyhow::Result<()> {
+    let mut reader = ScratchReader::open(scratch_path)?;
+    let mut frame_index = 0;
+    while let Some((timestamp_ms, gray_image)) = reader.read_frame()? {
+        let _ = tx.send((frame_index, timestamp_ms, gray_image));
+        frame_index += 1;
+    }
     Ok(())
 }
 
 fn identify(identify_args: &IdentifyArgs) -> anyhow::Result<()> {
     let tvid: Tvid = serde_json::from_reader(BufReader::new(File::open(&identify_args.tvid)?))?;
-    let tvds: Tvds = serde_json::from_reader(BufReader::new(File::open(&identify_args.tvds)?))?;
+    let db = Db::open(&identify_args.db)?;
 
-    let mut result: Vec<IdResult> = tvds
-        .episodes
+    let candidates = db.candidate_episodes(
+        identify_args.tv_id,
+        identify_args.season,
+        identify_args.min,
+        identify_args.max,
+    )?;
+
+    let mut result: Vec<IdResult> = candidates
         .into_iter()
-        .filter(|&(ep_id, _)| {
-            identify_args.min.map(|min| ep_id >= min).unwrap_or(true)
-                && identify_args.max.map(|max| ep_id <= max).unwrap_or(true)
-        })
-        .map(|(ep_id, ep)| {
-            let squared_error: u32 = ep
-                .thumbnails
+        .map(|(ep_id, thumbnails)| {
+            let matches = tvid::search::nearest(&thumbnails, &tvid.hashes);
+            let squared_error: u32 = matches
                 .iter()
-                .map(|thumb_hash| {
-                    let distance = tvid
-                        .hashes
-                        .iter()
-                        .map(|tv_hash| tv_hash.distance(&thumb_hash))
-                        .min()
-                        .unwrap();
-
-                    eprintln!("{} {}", ep_id, distance);
-                    distance * distance
+                .map(|m| {
+                    eprintln!("{} {}", ep_id, m.distance);
+                    m.distance * m.distance
                 })
                 .sum();
-            let mse = squared_error * 1000 / (ep.thumbnails.len() as u32);
+            let mse = squared_error * 1000 / (thumbnails.len() as u32);
+            let best_match = matches
+                .iter()
+                .min_by_key(|m| m.distance)
+                .expect("an episode must have at least one thumbnail");
             IdResult {
                 mse,
                 episode: ep_id,
+                timestamp_ms: tvid.timestamps[best_match.reference_index],
             }
         })
         .collect();
@@ -341,29 +490,45 @@ fn identify(identify_args: &IdentifyArgs) -> anyhow::Result<()> {
     result.sort();
 
     for r in result {
-        println!("{:?}", r);
+        println!(
+            "mse {:6} episode {:4} at {}",
+            r.mse,
+            r.episode,
+            format_timecode(r.timestamp_ms)
+        );
     }
 
     Ok(())
 }
 
-fn compare(tvid_path: &Path, image_path: &Path) -> anyhow::Result<()> {
+fn compare(
+    tvid_path: &Path,
+    image_path: &Path,
+    preview: bool,
+    scratch_path: Option<&Path>,
+) -> anyhow::Result<()> {
     let tvid: Tvid = serde_json::from_reader(BufReader::new(File::open(tvid_path)?))?;
-    let gray_image = image::open(image_path)?.to_luma8();
+    let query_image = image::open(image_path)?;
+    let gray_image = query_image.to_luma8();
 
     let mut hasher = GradientHash::new();
 
     let image_hash = hasher.hash(&gray_image);
     println!("base {:02x?}", image_hash);
 
+    let distances = &tvid::search::batch_distances(&[image_hash], &tvid.hashes)[0];
+
     let mut results: Vec<CompareResult> = tvid
         .hashes
         .iter()
+        .zip(distances)
+        .zip(&tvid.timestamps)
         .enumerate()
-        .map(|(frame, hash)| CompareResult {
+        .map(|(frame, ((hash, &distance), &timestamp_ms))| CompareResult {
             hash: *hash,
-            distance: image_hash.distance(&hash),
+            distance,
             frame: frame as u64,
+            timestamp_ms,
         })
         .collect();
 
@@ -371,10 +536,34 @@ fn compare(tvid_path: &Path, image_path: &Path) -> anyhow::Result<()> {
 
     for result in &results[..20] {
         println!(
-            "{:02x?} dist {} frame {}",
-            result.hash, result.distance, result.frame
+            "{:02x?} dist {} frame {} at {}",
+            result.hash,
+            result.distance,
+            result.frame,
+            format_timecode(result.timestamp_ms)
         );
     }
 
+    if preview {
+        let scratch_path = scratch_path.expect("clap requires --scratch alongside --preview");
+        let best = &results[0];
+        let matched_frame = read_scratch_frame(scratch_path, best.frame)?
+            .ok_or_else(|| anyhow!("scratch cache has fewer frames than `tvid` expects"))?;
+        tvid::preview::show("query", &query_image)?;
+        tvid::preview::show("best match", &matched_frame.into())?;
+    }
+
     Ok(())
 }
+
+/// Read the frame at `frame_index` out of the scratch cache at `path`,
+/// written by `hash`'s `--reuse` cache.
+fn read_scratch_frame(path: &Path, frame_index: u64) -> anyhow::Result<Option<GrayImage>> {
+    let mut reader = ScratchReader::open(path)?;
+    for _ in 0..frame_index {
+        if reader.read_frame()?.is_none() {
+            return Ok(None);
+        }
+    }
+    Ok(reader.read_frame()?.map(|(_, image)| image))
+}
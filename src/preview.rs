@@ -0,0 +1,125 @@
+//! Inline terminal image previews.
+//!
+//! Prefers whichever inline-image protocol the terminal advertises, falling
+//! back to a coarse half-block renderer everywhere else.
+
+use std::io::{self, Cursor, Write};
+
+use base64::Engine;
+use image::{DynamicImage, GenericImageView, ImageFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Iterm2,
+    Kitty,
+    HalfBlock,
+}
+
+fn detect_protocol() -> Protocol {
+    if std::env::var_os("KITTY_WINDOW_ID").is_some() {
+        Protocol::Kitty
+    } else if std::env::var("TERM_PROGRAM").as_deref() == Ok("iTerm.app") {
+        Protocol::Iterm2
+    } else {
+        Protocol::HalfBlock
+    }
+}
+
+/// Print `label`, then render `image` inline using the best protocol the
+/// current terminal supports.
+pub fn show(label: &str, image: &DynamicImage) -> io::Result<()> {
+    println!("{label}");
+    match detect_protocol() {
+        Protocol::Iterm2 => write_iterm2(image),
+        Protocol::Kitty => write_kitty(image),
+        Protocol::HalfBlock => write_half_block(image),
+    }
+}
+
+fn encode_png(image: &DynamicImage) -> io::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(png)
+}
+
+/// iTerm2's inline image protocol: a single OSC 1337 escape carrying a
+/// base64-encoded image.
+fn write_iterm2(image: &DynamicImage) -> io::Result<()> {
+    let png = encode_png(image)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&png);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write!(
+        out,
+        "\x1b]1337;File=inline=1;size={}:{}\x07",
+        png.len(),
+        encoded
+    )?;
+    writeln!(out)
+}
+
+/// The Kitty graphics protocol, chunked to stay under its 4096-byte payload
+/// limit per escape sequence.
+fn write_kitty(image: &DynamicImage) -> io::Result<()> {
+    const CHUNK_SIZE: usize = 4096;
+
+    let png = encode_png(image)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&png);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(CHUNK_SIZE).collect();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let control = if i == 0 {
+            format!("a=T,f=100,m={more}")
+        } else {
+            format!("m={more}")
+        };
+        write!(
+            out,
+            "\x1b_G{};{}\x1b\\",
+            control,
+            std::str::from_utf8(chunk).expect("base64 is ASCII")
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Coarse fallback for terminals with no inline-image protocol: render two
+/// vertically-stacked pixels per cell using the upper-half-block glyph,
+/// colored by foreground/background.
+fn write_half_block(image: &DynamicImage) -> io::Result<()> {
+    const TARGET_WIDTH: u32 = 64;
+
+    let (width, height) = image.dimensions();
+    let mut target_height = height * TARGET_WIDTH / width.max(1);
+    target_height += target_height % 2; // keep it even so rows pair up
+    let target_height = target_height.max(2);
+
+    let small = image.resize_exact(
+        TARGET_WIDTH,
+        target_height,
+        image::imageops::FilterType::Triangle,
+    );
+    let rgb = small.to_rgb8();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for y in (0..rgb.height()).step_by(2) {
+        for x in 0..rgb.width() {
+            let top = rgb.get_pixel(x, y).0;
+            let bottom = rgb.get_pixel(x, y + 1).0;
+            write!(
+                out,
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+            )?;
+        }
+        writeln!(out, "\x1b[0m")?;
+    }
+    Ok(())
+}
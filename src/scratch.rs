@@ -0,0 +1,82 @@
+//! A small on-disk cache of decoded, cropped luma frames.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use image::GrayImage;
+
+/// Appends raw cropped luma frames to a scratch file, prefixed by a small
+/// header recording their shared dimensions.
+pub struct ScratchWriter {
+    file: BufWriter<File>,
+}
+
+impl ScratchWriter {
+    pub fn create(path: impl AsRef<Path>, width: u32, height: u32) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&width.to_le_bytes())?;
+        file.write_all(&height.to_le_bytes())?;
+        Ok(Self { file })
+    }
+
+    /// Append one frame's presentation timestamp (in milliseconds) and raw
+    /// luma bytes, which must be `width * height` bytes as given to
+    /// [`ScratchWriter::create`].
+    pub fn write_frame(&mut self, timestamp_ms: i64, luma: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(&timestamp_ms.to_le_bytes())?;
+        self.file.write_all(luma)
+    }
+}
+
+/// Streams raw cropped luma frames back out of a scratch file written by
+/// [`ScratchWriter`].
+pub struct ScratchReader {
+    file: BufReader<File>,
+    width: u32,
+    height: u32,
+}
+
+impl ScratchReader {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut dims = [0u8; 8];
+        file.read_exact(&mut dims)?;
+        let width = u32::from_le_bytes(dims[0..4].try_into().unwrap());
+        let height = u32::from_le_bytes(dims[4..8].try_into().unwrap());
+        Ok(Self {
+            file,
+            width,
+            height,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Read the next frame's timestamp (in milliseconds) and image, or
+    /// `None` once the cache is exhausted.
+    pub fn read_frame(&mut self) -> std::io::Result<Option<(i64, GrayImage)>> {
+        let mut timestamp_bytes = [0u8; 8];
+        match self.file.read_exact(&mut timestamp_bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let timestamp_ms = i64::from_le_bytes(timestamp_bytes);
+
+        let mut buf = vec![0u8; (self.width * self.height) as usize];
+        self.file.read_exact(&mut buf)?;
+        Ok(Some((
+            timestamp_ms,
+            GrayImage::from_raw(self.width, self.height, buf).unwrap(),
+        )))
+    }
+}
@@ -0,0 +1,76 @@
+//! Batch nearest-neighbor search over hashes, dispatched to whichever CPU
+//! feature level (AVX2, SSE4.2, or scalar) is available at runtime.
+
+use multiversion::multiversion;
+
+use crate::Hash;
+
+/// The closest reference hash (by index) to one query hash, and their
+/// distance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub reference_index: usize,
+    pub distance: u32,
+}
+
+/// For every hash in `queries`, return its Hamming distance to every hash in
+/// `references`, as one row of distances per query.
+pub fn batch_distances(queries: &[Hash], references: &[Hash]) -> Vec<Vec<u32>> {
+    let reference_lanes: Vec<u64> = references.iter().map(|hash| hash.to_lane()).collect();
+    queries
+        .iter()
+        .map(|query| distances_to_one(query.to_lane(), &reference_lanes))
+        .collect()
+}
+
+/// For every hash in `queries`, find the closest hash in `references`.
+///
+/// Panics if `references` is empty.
+pub fn nearest(queries: &[Hash], references: &[Hash]) -> Vec<Match> {
+    batch_distances(queries, references)
+        .into_iter()
+        .map(|distances| {
+            let (reference_index, &distance) = distances
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, &distance)| distance)
+                .expect("references must not be empty");
+            Match {
+                reference_index,
+                distance,
+            }
+        })
+        .collect()
+}
+
+/// The popcount-over-XOR kernel, specialized for multiple CPU feature
+/// levels at runtime so the compiler can vectorize the inner loop.
+#[multiversion(targets("x86_64+avx2", "x86_64+sse4.2"))]
+fn distances_to_one(query: u64, references: &[u64]) -> Vec<u32> {
+    distances_to_one_scalar(query, references)
+}
+
+/// Plain scalar reference for [`distances_to_one`], used to check that the
+/// dispatched AVX2/SSE4.2 variants agree with it.
+fn distances_to_one_scalar(query: u64, references: &[u64]) -> Vec<u32> {
+    references
+        .iter()
+        .map(|&reference| (query ^ reference).count_ones())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatched_matches_scalar_reference() {
+        let query = 0x0123_4567_89ab_cdef;
+        let references: Vec<u64> = (0..1000u64).map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15)).collect();
+
+        assert_eq!(
+            distances_to_one(query, &references),
+            distances_to_one_scalar(query, &references)
+        );
+    }
+}